@@ -4,9 +4,9 @@
 use core::time::Duration;
 
 use esp32c3_hal::{
-    clock::ClockControl, 
-    peripherals::Peripherals, 
-    prelude::*, 
+    clock::ClockControl,
+    peripherals::Peripherals,
+    prelude::*,
     Delay,
     IO,
     Rtc,
@@ -17,15 +17,28 @@ use esp32c3_hal::{
         get_wakeup_cause,
         sleep::{
             TimerWakeupSource,
-            RtcioWakeupSource, 
+            RtcioWakeupSource,
             WakeupLevel
         },
     },
-    systimer::SystemTimer, 
+    systimer::SystemTimer,
     Rng,
     efuse::Efuse,
 };
 
+#[cfg(not(feature = "gateway"))]
+use esp32c3_hal::{embassy, timer::TimerGroup};
+#[cfg(not(feature = "gateway"))]
+use embassy_executor::Spawner;
+#[cfg(not(feature = "gateway"))]
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+#[cfg(not(feature = "gateway"))]
+use embassy_time::{Duration as EmbassyDuration, Timer as EmbassyTimer};
+#[cfg(not(feature = "gateway"))]
+use embassy_futures::select::select;
+#[cfg(not(feature = "gateway"))]
+use static_cell::StaticCell;
+
 use esp_wifi::{
     initialize, 
     EspWifiInitFor,
@@ -40,12 +53,46 @@ use esp_wifi::{
 use esp_backtrace as _;
 use esp_println::println;
 
+#[cfg(feature = "gateway")]
+mod mqtt;
+
+#[cfg(feature = "gateway")]
+use esp_wifi::{
+    current_millis,
+    wifi::{utils::create_network_interface, ClientConfiguration, Configuration, WifiMode, WifiStaDevice},
+    wifi_interface::WifiStack,
+};
+#[cfg(feature = "gateway")]
+use smoltcp::{iface::SocketStorage, wire::IpAddress};
+
+#[cfg(feature = "gateway")]
+const GATEWAY_SSID: &str = env!("GATEWAY_WIFI_SSID");
+#[cfg(feature = "gateway")]
+const GATEWAY_PASSWORD: &str = env!("GATEWAY_WIFI_PASSWORD");
+#[cfg(feature = "gateway")]
+const MQTT_BROKER_ADDR: (IpAddress, u16) = (IpAddress::v4(192, 168, 1, 10), 1883);
+
+// Runtime-tunable settings the server can rewrite via the SCPI-style command
+// channel below. Packed into one struct so it lives at a single rtc_fast slot.
+#[derive(Clone, Copy)]
+struct Config {
+    sleep_secs: u32,
+    wake_level: WakeupLevel,
+}
+
 #[ram(rtc_fast, uninitialized)]
-static mut WAKEUP_LEVEL:WakeupLevel = WakeupLevel::High;
+static mut CONFIG: Config = Config {
+    sleep_secs: 5,
+    wake_level: WakeupLevel::High,
+};
 #[ram(rtc_fast, uninitialized)]
 static mut SERVER_ADDR: [u8; 6] = BROADCAST_ADDRESS;
 #[ram(rtc_fast, uninitialized)]
 static mut TIMER_SLEEP: bool = false;
+#[ram(rtc_fast, uninitialized)]
+static mut SERVER_LMK: [u8; 16] = [0u8; 16];
+#[ram(rtc_fast, uninitialized)]
+static mut HAVE_LMK: bool = false;
 
 macro_rules! read_volatile {
     ($var:expr) => {
@@ -61,11 +108,11 @@ macro_rules! write_volatile {
 
 macro_rules! negate_wakeup_level {
     () => {
-        if read_volatile!(WAKEUP_LEVEL) == WakeupLevel::High {
-            write_volatile!(WAKEUP_LEVEL,WakeupLevel::Low);
+        if read_volatile!(CONFIG.wake_level) == WakeupLevel::High {
+            write_volatile!(CONFIG.wake_level,WakeupLevel::Low);
         }
         else {
-            write_volatile!(WAKEUP_LEVEL,WakeupLevel::High);
+            write_volatile!(CONFIG.wake_level,WakeupLevel::High);
         }
     };
 }
@@ -80,10 +127,488 @@ macro_rules! begin_sleep {
     };
 }
 
+// PMK for this deployment, injected at build time (one value per flashed
+// fleet, not one literal shared by every fleet ever built) the same way
+// `GATEWAY_SSID`/`GATEWAY_PASSWORD` are above. Set `NODE_PMK` to exactly 16
+// ASCII bytes, e.g. `NODE_PMK=$(openssl rand -hex 8) cargo espflash ...`.
+// Only used to wrap the per-server LMK handed out during the broadcast
+// handshake below.
+const PMK: [u8; 16] = {
+    let bytes = env!("NODE_PMK").as_bytes();
+    assert!(bytes.len() == 16, "NODE_PMK must be exactly 16 bytes");
+    let mut out = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+};
 
-#[entry]
-fn main() -> ! {
+// Opcode for this node's broadcast discovery request. Layout:
+// [OPCODE_DISCOVER, nonce (4 bytes, little-endian)]. The nonce is not sent
+// back; the server's `OPCODE_LMK` reply is keyed to it through its auth tag
+// and keystream (see `pmk_auth_tag` below) so a stale or replayed reply
+// can't be mistaken for a fresh handshake.
+const OPCODE_DISCOVER: u8 = 0xF0;
+
+// Opcode prefixing the LMK blob the server sends back to a newly-discovered
+// node, addressed to that node's MAC. Layout: [OPCODE_LMK,
+// lmk_ciphertext[0..16], auth_tag (4 bytes, little-endian)]. `lmk_ciphertext`
+// is the real LMK XORed with `pmk_keystream(nonce)` so it's never on the air
+// in cleartext, and `auth_tag` is `pmk_auth_tag(nonce, lmk_ciphertext)` —
+// proof the reply came from someone who holds `PMK` for *this* handshake,
+// not just whoever answered fastest.
+const OPCODE_LMK: u8 = 0xF1;
+
+// Lightweight keyed checksum binding a nonce and message to `PMK`, used to
+// authenticate an `OPCODE_LMK` reply without pulling in a full HMAC
+// implementation for this no_std, no-crypto-crate target. Not a
+// cryptographically strong MAC, but it closes the "accept any OPCODE_LMK
+// frame addressed to us" gap: a reply is only trusted if its tag matches
+// what someone holding `PMK` would have produced for this nonce and payload.
+fn pmk_auth_tag(nonce: u32, msg: &[u8]) -> u32 {
+    let mut h: u32 = nonce;
+    for &b in PMK.iter().chain(msg.iter()) {
+        h = h.rotate_left(5) ^ (b as u32).wrapping_mul(0x9E3779B1);
+    }
+    h
+}
+
+// Lightweight XOR keystream derived from `PMK` and the discovery nonce, used
+// to keep the LMK off the air in cleartext. Same caveat as `pmk_auth_tag`:
+// this is a keyed PRF good enough to stop passive sniffing on this no_std
+// target, not a vetted cipher — swap in a real AEAD if a deployment's threat
+// model needs one.
+fn pmk_keystream(nonce: u32) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (pmk_auth_tag(nonce ^ (i as u32).wrapping_mul(0x01000193), &[]) & 0xFF) as u8;
+    }
+    out
+}
+
+// Opcode prefixing an ASCII SCPI-style command frame from the server, e.g.
+// `[OPCODE_SCPI, b"CONF:SLEEP 10"]`. See `parse_scpi` below for the grammar.
+const OPCODE_SCPI: u8 = 0xC0;
+
+// Opcode the server uses to ack a movement update by sequence number.
+// Layout: [OPCODE_ACK, seq_lo, seq_hi].
+const OPCODE_ACK: u8 = 0xA1;
+
+// How many unacked events the rtc_fast ring buffer holds. Once full, the
+// buffer collapses to a single entry holding the newest event.
+const EVENT_QUEUE_CAP: usize = 4;
+const RETRY_COUNT: u8 = 3;
+const RETRY_BACKOFF_MS: u32 = 150;
+
+#[derive(Clone, Copy)]
+struct QueuedEvent {
+    seq: u16,
+    state: u8,
+    // Epoch milliseconds at capture time, or 0 if no time sync has landed yet.
+    epoch_ms: u64,
+    used: bool,
+}
+
+const EMPTY_EVENT: QueuedEvent = QueuedEvent { seq: 0, state: 0, epoch_ms: 0, used: false };
+
+#[ram(rtc_fast, uninitialized)]
+static mut EVENT_QUEUE: [QueuedEvent; EVENT_QUEUE_CAP] = [EMPTY_EVENT; EVENT_QUEUE_CAP];
+#[ram(rtc_fast, uninitialized)]
+static mut NEXT_SEQ: u16 = 0;
+
+// Queues a movement event. Once the ring buffer is full, the new event goes
+// into the first slot and every other entry is dropped (keeping only the
+// newest state).
+fn queue_push(state: u8, epoch_ms: u64) -> u16 {
+    let seq = read_volatile!(NEXT_SEQ);
+    let mut queue = read_volatile!(EVENT_QUEUE);
+    if let Some(slot) = queue.iter_mut().find(|e| !e.used) {
+        *slot = QueuedEvent { seq, state, epoch_ms, used: true };
+    } else {
+        println!("Event queue full, coalescing to most recent state");
+        queue[0] = QueuedEvent { seq, state, epoch_ms, used: true };
+        for e in queue.iter_mut().skip(1) {
+            e.used = false;
+        }
+    }
+    write_volatile!(EVENT_QUEUE, queue);
+    write_volatile!(NEXT_SEQ, seq.wrapping_add(1));
+    seq
+}
+
+// Drops an entry once the server has acked its sequence number.
+fn queue_ack(seq: u16) {
+    let mut queue = read_volatile!(EVENT_QUEUE);
+    for e in queue.iter_mut() {
+        if e.used && e.seq == seq {
+            e.used = false;
+        }
+    }
+    write_volatile!(EVENT_QUEUE, queue);
+}
+
+// True once any event is still waiting on an ack.
+fn queue_has_pending() -> bool {
+    read_volatile!(EVENT_QUEUE).iter().any(|e| e.used)
+}
+
+// Retransmits every still-unacked event, each with a bounded number of
+// attempts and a short backoff, so a dropped frame doesn't silently lose a
+// movement event across sleep cycles. Gives up on the whole batch and falls
+// back to re-pairing if an encrypted send keeps failing, since that implies
+// the server no longer holds our LMK. Each frame carries the epoch
+// timestamp captured when the event was queued: [0x22, seq_lo, seq_hi,
+// state, epoch_ms (8 bytes, little-endian)].
+//
+// Takes the shared `esp_now` mutex rather than an exclusive reference so the
+// receive task can keep polling for acks between retries.
+#[cfg(not(feature = "gateway"))]
+async fn flush_event_queue(esp_now: &'static Mutex<CriticalSectionRawMutex, EspNow<'static>>, server_addr: &[u8; 6]) {
+    let queue = read_volatile!(EVENT_QUEUE);
+    for entry in queue.iter().filter(|e| e.used) {
+        let mut frame = [0u8; 12];
+        frame[0] = 0x22;
+        frame[1] = (entry.seq & 0xFF) as u8;
+        frame[2] = (entry.seq >> 8) as u8;
+        frame[3] = entry.state;
+        frame[4..12].copy_from_slice(&entry.epoch_ms.to_le_bytes());
+
+        let mut sent_ok = false;
+        for attempt in 0..RETRY_COUNT {
+            let res = {
+                let mut esp_now = esp_now.lock().await;
+                esp_now.send(server_addr, &frame).unwrap().await
+            };
+            println!("Sending seq {} (attempt {}): {:?}", entry.seq, attempt + 1, res);
+            if res.is_ok() {
+                sent_ok = true;
+                break;
+            }
+            EmbassyTimer::after(EmbassyDuration::from_millis(RETRY_BACKOFF_MS as u64)).await;
+        }
+        if !sent_ok && read_volatile!(HAVE_LMK) {
+            println!("Encrypted send failed after {} attempts, dropping LMK and re-pairing", RETRY_COUNT);
+            write_volatile!(HAVE_LMK, false);
+            write_volatile!(SERVER_ADDR, BROADCAST_ADDRESS);
+            break;
+        }
+    }
+}
+
+// SystemTimer tick rate, used to convert intra-boot tick deltas to milliseconds.
+const TICKS_PER_MS: u64 = SystemTimer::TICKS_PER_SECOND / 1000;
+
+// Opcode for this node's outgoing `TIME?` query (no payload beyond the opcode).
+const OPCODE_TIME_QUERY: u8 = 0xA4;
+// Opcode for the server's reply: [OPCODE_TIME_REPLY, epoch_ms (8 bytes, little-endian)].
+const OPCODE_TIME_REPLY: u8 = 0xA5;
+
+#[ram(rtc_fast, uninitialized)]
+static mut EPOCH_MS: u64 = 0;
+#[ram(rtc_fast, uninitialized)]
+static mut EPOCH_TICK: u64 = 0;
+#[ram(rtc_fast, uninitialized)]
+static mut HAVE_EPOCH: bool = false;
+
+// Re-bases the stored epoch to "now" at the start of this boot. `SystemTimer`
+// resets across deep sleep, so the tick delta it would otherwise take can't
+// tell us how long we slept; a `Timer` wakeup is the one case where we know
+// the elapsed time exactly (the configured sleep interval), so only that
+// case advances the stored epoch. A `Gpio` wakeup keeps the last known epoch
+// until the next `TIME?` sync lands.
+fn rebase_epoch(wake_reason: SleepSource) {
+    if !read_volatile!(HAVE_EPOCH) {
+        return;
+    }
+    if let SleepSource::Timer = wake_reason {
+        let elapsed_ms = read_volatile!(CONFIG.sleep_secs) as u64 * 1000;
+        write_volatile!(EPOCH_MS, read_volatile!(EPOCH_MS) + elapsed_ms);
+    }
+    write_volatile!(EPOCH_TICK, SystemTimer::now());
+}
+
+// Current epoch milliseconds, accounting for ticks elapsed since `rebase_epoch`
+// ran this boot. `None` until the first successful `TIME?` sync.
+fn current_epoch_ms() -> Option<u64> {
+    if !read_volatile!(HAVE_EPOCH) {
+        return None;
+    }
+    let elapsed_ticks = SystemTimer::now().saturating_sub(read_volatile!(EPOCH_TICK));
+    Some(read_volatile!(EPOCH_MS) + elapsed_ticks / TICKS_PER_MS)
+}
+
+// Applies an unsolicited or queried `TIME?` reply from the server.
+fn handle_time_reply(payload: &[u8]) {
+    if payload.len() < 9 {
+        return;
+    }
+    let mut epoch_bytes = [0u8; 8];
+    epoch_bytes.copy_from_slice(&payload[1..9]);
+    write_volatile!(EPOCH_MS, u64::from_le_bytes(epoch_bytes));
+    write_volatile!(EPOCH_TICK, SystemTimer::now());
+    write_volatile!(HAVE_EPOCH, true);
+    println!("Time synced: {} ms since epoch", read_volatile!(EPOCH_MS));
+}
+
+enum ScpiCommand {
+    ConfSleep(u32),
+    ConfWakeLevel(WakeupLevel),
+    StatBattQuery,
+}
+
+// A tiny SCPI-inspired parser: ':' separates hierarchy levels, a trailing '?'
+// marks a query, everything else is a space-separated argument. Only the
+// handful of commands this node understands are implemented.
+fn parse_scpi(text: &str) -> Option<ScpiCommand> {
+    let text = text.trim();
+    let (header, arg) = match text.split_once(' ') {
+        Some((header, arg)) => (header, arg.trim()),
+        None => (text, ""),
+    };
+    let mut levels = header.split(':');
+    match levels.next()? {
+        "CONF" => match levels.next()? {
+            "SLEEP" => arg.parse().ok().map(ScpiCommand::ConfSleep),
+            "WAKE" => {
+                if levels.next()? != "LEVEL" {
+                    return None;
+                }
+                match arg {
+                    "HIGH" => Some(ScpiCommand::ConfWakeLevel(WakeupLevel::High)),
+                    "LOW" => Some(ScpiCommand::ConfWakeLevel(WakeupLevel::Low)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        "STAT" => match levels.next()? {
+            "BATT?" => Some(ScpiCommand::StatBattQuery),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Applies a command frame from the server and acknowledges it. No-op if the
+// frame isn't an SCPI command frame or fails to parse.
+async fn handle_scpi_frame(esp_now: &mut EspNow<'_>, server_addr: &[u8; 6], payload: &[u8]) {
+    let [OPCODE_SCPI, rest @ ..] = payload else {
+        return;
+    };
+    let Ok(text) = core::str::from_utf8(rest) else {
+        println!("SCPI frame was not valid UTF-8: {:?}", rest);
+        return;
+    };
+    let Some(command) = parse_scpi(text) else {
+        println!("Unrecognized SCPI command: {}", text);
+        return;
+    };
+    match command {
+        ScpiCommand::ConfSleep(secs) => {
+            write_volatile!(CONFIG.sleep_secs, secs);
+            println!("CONF:SLEEP set to {}s", secs);
+        }
+        ScpiCommand::ConfWakeLevel(level) => {
+            write_volatile!(CONFIG.wake_level, level);
+            println!("CONF:WAKE:LEVEL set to {:?}", level);
+        }
+        ScpiCommand::StatBattQuery => {
+            println!("STAT:BATT? received, no battery sensing implemented");
+        }
+    }
+    let res = esp_now.send(server_addr, b"OK").unwrap().await;
+    println!("SCPI ack result: {:?}", res);
+}
+
+// Everything below coordinates the three concurrent phases of a sensor
+// wakeup — discovery/handshake, sending the queued events, and listening
+// for acks/commands — over one shared `EspNow` handle, instead of the old
+// linear blocking sequence. `DISCOVERY_DONE` gates the other two tasks since
+// they both depend on `SERVER_ADDR`/`HAVE_LMK` being settled first; `main`
+// waits on `SEND_DONE` and `RECEIVE_DONE` before entering deep sleep.
+#[cfg(not(feature = "gateway"))]
+static ESP_NOW: StaticCell<Mutex<CriticalSectionRawMutex, EspNow<'static>>> = StaticCell::new();
+#[cfg(not(feature = "gateway"))]
+static ESP_WIFI_INIT: StaticCell<esp_wifi::EspWifiInitialization> = StaticCell::new();
+#[cfg(not(feature = "gateway"))]
+static DISCOVERY_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+#[cfg(not(feature = "gateway"))]
+static SEND_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+#[cfg(not(feature = "gateway"))]
+static RECEIVE_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Broadcasts for a server if we don't have one yet, and applies whatever
+// handshake reply comes back (server address, optional LMK). Signals
+// `DISCOVERY_DONE` unconditionally so the other tasks can proceed.
+#[cfg(not(feature = "gateway"))]
+#[embassy_executor::task]
+async fn discovery_task(esp_now: &'static Mutex<CriticalSectionRawMutex, EspNow<'static>>) {
+    let server_addr = read_volatile!(SERVER_ADDR);
+    if server_addr == BROADCAST_ADDRESS {
+        // `SystemTimer::now()` resets across deep sleep but still varies
+        // boot-to-boot (it free-runs from power-on), which is enough entropy
+        // to stop a recorded reply from a previous handshake being replayed
+        // against a later one; it's not a hardware RNG-grade nonce.
+        let nonce = SystemTimer::now() as u32;
+        let mut request = [0u8; 5];
+        request[0] = OPCODE_DISCOVER;
+        request[1..5].copy_from_slice(&nonce.to_le_bytes());
 
+        println!("Sending broadcast information");
+        let reply = {
+            let mut esp_now = esp_now.lock().await;
+            let res = esp_now.send(&server_addr, &request).unwrap().await;
+            println!("Send result: {:?}", res);
+            embassy_time::with_timeout(EmbassyDuration::from_millis(1000), esp_now.receive_async()).await
+        };
+
+        match reply {
+            Ok(data) => {
+                // Only adopt `data.info.src_address` as our server once it's
+                // proven it holds `PMK` for *this* handshake — accepting any
+                // reply addressed to our MAC let an attacker on channel 1
+                // spoof the server just by answering first.
+                if data.info.dst_address == Efuse::get_mac_address()
+                    && data.data.len() >= 21
+                    && data.data[0] == OPCODE_LMK
+                {
+                    let lmk_ciphertext = &data.data[1..17];
+                    let mut tag_bytes = [0u8; 4];
+                    tag_bytes.copy_from_slice(&data.data[17..21]);
+                    let tag = u32::from_le_bytes(tag_bytes);
+                    if tag == pmk_auth_tag(nonce, lmk_ciphertext) {
+                        let keystream = pmk_keystream(nonce);
+                        let mut lmk = [0u8; 16];
+                        for i in 0..16 {
+                            lmk[i] = lmk_ciphertext[i] ^ keystream[i];
+                        }
+                        println!("Setting server address to {:?}", data.info.src_address);
+                        write_volatile!(SERVER_ADDR, data.info.src_address);
+                        write_volatile!(SERVER_LMK, lmk);
+                        write_volatile!(HAVE_LMK, true);
+                        println!("Received LMK from server");
+                    } else {
+                        println!("OPCODE_LMK reply failed auth tag check, ignoring");
+                    }
+                }
+                println!("Received data: {:?}", data);
+            }
+            Err(_) => println!("No discovery reply within timeout"),
+        }
+    }
+    DISCOVERY_DONE.signal(());
+}
+
+// Peers with the known server (if any), flushes the unacked event queue, and
+// kicks off a time sync if we don't have one yet.
+#[cfg(not(feature = "gateway"))]
+#[embassy_executor::task]
+async fn send_task(esp_now: &'static Mutex<CriticalSectionRawMutex, EspNow<'static>>) {
+    DISCOVERY_DONE.wait().await;
+    // `Signal::wait` takes the value out; signal again for the other waiter.
+    DISCOVERY_DONE.signal(());
+
+    let server_addr = read_volatile!(SERVER_ADDR);
+    if server_addr != BROADCAST_ADDRESS {
+        let have_lmk = read_volatile!(HAVE_LMK);
+        let lmk = read_volatile!(SERVER_LMK);
+        let mut esp_now = esp_now.lock().await;
+        let _ = esp_now.add_peer(PeerInfo {
+            peer_address: server_addr,
+            lmk: if have_lmk { Some(lmk) } else { None },
+            channel: None,
+            encrypt: have_lmk,
+        });
+    }
+
+    // Send regardless of whether discovery resolved a server this boot:
+    // `server_addr` falls back to `BROADCAST_ADDRESS`, which `EspNow::send`
+    // accepts without a peered entry (same as the discovery broadcast
+    // above). Gating these on a resolved peer meant queued events just sat
+    // in rtc_fast RAM until discovery eventually succeeded, undermining the
+    // at-least-once delivery guarantee `flush_event_queue` exists for.
+    if queue_has_pending() {
+        flush_event_queue(esp_now, &server_addr).await;
+    }
+
+    if !read_volatile!(HAVE_EPOCH) {
+        println!("Requesting time sync");
+        let mut esp_now = esp_now.lock().await;
+        let res = esp_now.send(&server_addr, &[OPCODE_TIME_QUERY]).unwrap().await;
+        println!("TIME? send result: {:?}", res);
+    }
+
+    SEND_DONE.signal(());
+}
+
+// Grace period given to the last ack after `send_task` finishes, so an ack
+// for the final retry (still in flight over the air) isn't cut off the
+// instant the last send completes.
+#[cfg(not(feature = "gateway"))]
+const RECEIVE_GRACE_MS: u64 = 200;
+
+// Longest single wait on `receive_async` before `receive_task` lets go of
+// the shared `EspNow` lock.
+#[cfg(not(feature = "gateway"))]
+const RECEIVE_POLL_MS: u64 = 20;
+
+// Listens for acks, SCPI commands, and time-sync replies until `send_task`
+// is done retrying and a short grace period elapses. `flush_event_queue`
+// can retry for up to `RETRY_COUNT * RETRY_BACKOFF_MS` per unacked entry
+// while sending concurrently, so gating on a fixed timeout here (rather
+// than on `SEND_DONE`) let sends outlive the receive window and left their
+// acks unprocessed for the rest of the wake cycle.
+#[cfg(not(feature = "gateway"))]
+#[embassy_executor::task]
+async fn receive_task(esp_now: &'static Mutex<CriticalSectionRawMutex, EspNow<'static>>) {
+    DISCOVERY_DONE.wait().await;
+    // `Signal::wait` takes the value out; signal again for the other waiter.
+    DISCOVERY_DONE.signal(());
+    let server_addr = read_volatile!(SERVER_ADDR);
+
+    let listen = async {
+        loop {
+            // Bounded wait, then yield, so the lock is not held across an
+            // idle receive while `send_task` is waiting for it.
+            let received = {
+                let mut esp_now = esp_now.lock().await;
+                embassy_time::with_timeout(EmbassyDuration::from_millis(RECEIVE_POLL_MS), esp_now.receive_async()).await
+            };
+            let Ok(data) = received else {
+                embassy_futures::yield_now().await;
+                continue;
+            };
+            if data.info.dst_address != Efuse::get_mac_address() {
+                continue;
+            }
+            match data.data.first() {
+                Some(&OPCODE_SCPI) => {
+                    let mut esp_now = esp_now.lock().await;
+                    handle_scpi_frame(&mut esp_now, &server_addr, &data.data).await;
+                }
+                Some(&OPCODE_TIME_REPLY) => handle_time_reply(&data.data),
+                _ if data.data.len() >= 3 && data.data[0] == OPCODE_ACK => {
+                    queue_ack(u16::from_le_bytes([data.data[1], data.data[2]]));
+                }
+                _ => {}
+            }
+        }
+    };
+    let wait_for_sends = async {
+        SEND_DONE.wait().await;
+        // `main` waits on `SEND_DONE` too; signal again so it is released.
+        SEND_DONE.signal(());
+        EmbassyTimer::after(EmbassyDuration::from_millis(RECEIVE_GRACE_MS)).await;
+    };
+    select(listen, wait_for_sends).await;
+    RECEIVE_DONE.signal(());
+}
+
+#[cfg(not(feature = "gateway"))]
+#[embassy_executor::main]
+async fn main(spawner: Spawner) -> ! {
     let peripherals = Peripherals::take();
     let system = peripherals.SYSTEM.split();
 
@@ -107,19 +632,19 @@ fn main() -> ! {
     let mut send_update = false;
     match wake_reason{
         SleepSource::Timer =>{
-            if read_volatile!(WAKEUP_LEVEL) == WakeupLevel::High {
+            if read_volatile!(CONFIG.wake_level) == WakeupLevel::High {
                 write_volatile!(TIMER_SLEEP,false);
                 send_update = true;
             }
         }
         SleepSource::Gpio=>{
-            if read_volatile!(WAKEUP_LEVEL) == WakeupLevel::High && read_volatile!(TIMER_SLEEP) == false {
+            if read_volatile!(CONFIG.wake_level) == WakeupLevel::High && read_volatile!(TIMER_SLEEP) == false {
                 send_update = true;
             }
-            else if read_volatile!(WAKEUP_LEVEL) == WakeupLevel::High && read_volatile!(TIMER_SLEEP) == true {
+            else if read_volatile!(CONFIG.wake_level) == WakeupLevel::High && read_volatile!(TIMER_SLEEP) == true {
                 write_volatile!(TIMER_SLEEP,false);
             }
-            else if read_volatile!(WAKEUP_LEVEL) == WakeupLevel::Low {
+            else if read_volatile!(CONFIG.wake_level) == WakeupLevel::Low {
                 write_volatile!(TIMER_SLEEP,true);
             }
             negate_wakeup_level!();
@@ -127,22 +652,81 @@ fn main() -> ! {
         SleepSource::Undefined=> write_volatile!(SERVER_ADDR,BROADCAST_ADDRESS),
         _ => (),
     }
+    rebase_epoch(wake_reason);
 
-    let timer_wakeup = TimerWakeupSource::new(Duration::from_secs(5));
+    let timer_wakeup = TimerWakeupSource::new(Duration::from_secs(read_volatile!(CONFIG.sleep_secs) as u64));
     let wakeup_pins: &mut [(&mut dyn RTCPinWithResistors, WakeupLevel)] = &mut [
-        (&mut wakeup_pin, read_volatile!(WAKEUP_LEVEL)),
+        (&mut wakeup_pin, read_volatile!(CONFIG.wake_level)),
         ];
     let rtcio = RtcioWakeupSource::new(wakeup_pins);
 
-    // send_update = true;
-    // write_volatile!(TIMER_SLEEP,true);
-    if read_volatile!(SERVER_ADDR) != BROADCAST_ADDRESS && send_update == false  {
+    if send_update {
+        queue_push(wakeup_pin_state as u8, current_epoch_ms().unwrap_or(0));
+    }
+
+    if read_volatile!(SERVER_ADDR) != BROADCAST_ADDRESS && send_update == false && !queue_has_pending() {
         begin_sleep!(rtc, rtcio, timer_wakeup, delay);
     }
-    
-    
+
+    let timer_group0 = TimerGroup::new(peripherals.TIMG0, &clocks);
+    embassy::init(&clocks, timer_group0.timer0);
+
     let timer = SystemTimer::new(peripherals.SYSTIMER).alarm0;
-    let _init = initialize(
+    let init = ESP_WIFI_INIT.init(
+        initialize(
+            EspWifiInitFor::Wifi,
+            timer,
+            Rng::new(peripherals.RNG),
+            system.radio_clock_control,
+            &clocks,
+        )
+        .unwrap(),
+    );
+
+    let mut esp_now = EspNow::new(init, peripherals.WIFI).unwrap();
+    esp_now.set_channel(1).unwrap();
+    esp_now.set_pmk(&PMK).unwrap();
+    println!("My MAC: {:?}", Efuse::get_mac_address());
+
+    let esp_now = ESP_NOW.init(Mutex::new(esp_now));
+
+    spawner.spawn(discovery_task(esp_now)).ok();
+    spawner.spawn(send_task(esp_now)).ok();
+    spawner.spawn(receive_task(esp_now)).ok();
+
+    SEND_DONE.wait().await;
+    RECEIVE_DONE.wait().await;
+
+    begin_sleep!(rtc, rtcio, timer_wakeup, delay);
+}
+
+// Gateway role: joins the site Wi-Fi as a station, keeps the ESP-NOW
+// receiver active, and republishes every movement frame it hears as an MQTT
+// PUBLISH. Unlike the sensor role it never deep-sleeps. Built with
+// `--features gateway` so a board is flashed as either a sensor or the
+// gateway, never both.
+//
+// NOTE: ESP-NOW and the Wi-Fi STA interface both ride the same radio.
+// `steal()` hands out a second logical handle to the same WIFI peripheral
+// because no constructor here produces both from one `init`/`WIFI` pair, but
+// that means `EspNow` and `WifiStack` end up driving the same underlying
+// radio/ISR state through independently-owned handles with no
+// synchronization between them — real aliasing, not just an API
+// inconvenience. This tree has no Cargo.toml/lockfile, so the exact esp-wifi
+// version (and whether it already exposes a sanctioned combined STA+ESP-NOW
+// constructor) can't be confirmed here. TODO: verify against the pinned
+// esp-wifi version before merging, and drop this `steal()` for a combined
+// constructor if one exists.
+#[cfg(feature = "gateway")]
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take();
+    let system = peripherals.SYSTEM.split();
+    let clocks = ClockControl::max(system.clock_control).freeze();
+    let mut delay = Delay::new(&clocks);
+
+    let timer = SystemTimer::new(peripherals.SYSTIMER).alarm0;
+    let init = initialize(
         EspWifiInitFor::Wifi,
         timer,
         Rng::new(peripherals.RNG),
@@ -151,50 +735,92 @@ fn main() -> ! {
     )
     .unwrap();
 
-
-    let mut esp_now = EspNow::new(&_init,peripherals.WIFI).unwrap();
+    let esp_now_wifi = unsafe { esp32c3_hal::peripherals::WIFI::steal() };
+    let mut esp_now = EspNow::new(&init, esp_now_wifi).unwrap();
     esp_now.set_channel(1).unwrap();
-    
-    println!("My MAC: {:?}",Efuse::get_mac_address());
 
-    
+    let mut socket_set_entries: [SocketStorage; 3] = Default::default();
+    let (iface, device, mut controller, sockets) =
+        create_network_interface(&init, peripherals.WIFI, WifiMode::Sta(WifiStaDevice), &mut socket_set_entries)
+            .unwrap();
+    let mut wifi_stack = WifiStack::new(iface, device, sockets, current_millis);
 
-    let server_addr = read_volatile!(SERVER_ADDR);
-    if server_addr != BROADCAST_ADDRESS {
-        let _ = esp_now.add_peer(PeerInfo {
-            peer_address: server_addr,
-            lmk: None,
-            channel: None,
-            encrypt: false,
-        });
-    } else {
-        println!("Sending broadcast information");
-        let res =  esp_now.send(&server_addr, &[0xF0,0x00,0x22]).unwrap().wait();
-        println!("Send result: {:?}", res);
-        
-        delay.delay_ms(1000u32);
-        let response = esp_now.receive();
-        
-        match response {
-            Some(data) => {
-                if data.info.dst_address == Efuse::get_mac_address() {
-                    println!("Setting server address to {:?}", data.info.src_address);
-
-                    write_volatile!(SERVER_ADDR,data.info.src_address);
-                    send_update = false;
-                }
-                println!("Received data: {:?}", data);
-            },
-            None => println!("No data received")
-            
+    controller
+        .set_configuration(&Configuration::Client(ClientConfiguration {
+            ssid: GATEWAY_SSID.into(),
+            password: GATEWAY_PASSWORD.into(),
+            ..Default::default()
+        }))
+        .unwrap();
+    controller.start().unwrap();
+    controller.connect().unwrap();
+
+    println!("Gateway: waiting for Wi-Fi STA link...");
+    loop {
+        match controller.is_connected() {
+            Ok(true) => break,
+            Ok(false) => delay.delay_ms(500u32),
+            Err(err) => {
+                println!("Gateway: Wi-Fi connect error: {:?}", err);
+                delay.delay_ms(500u32);
+            }
         }
     }
-    if send_update {
-        println!("Sending update to {:?}", server_addr);
-        let res =  esp_now.send(&server_addr, &[0x22,wakeup_pin_state as u8]).unwrap().wait();
-        println!("Send result: {:?}", res);
+
+    println!("Gateway: waiting for DHCP lease...");
+    loop {
+        wifi_stack.work();
+        if wifi_stack.is_iface_up() {
+            println!("Gateway: IP info: {:?}", wifi_stack.get_ip_info());
+            break;
+        }
     }
 
-    begin_sleep!(rtc, rtcio, timer_wakeup, delay);
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = wifi_stack.get_socket(&mut rx_buffer, &mut tx_buffer);
+
+    println!("Gateway: connecting to MQTT broker at {:?}", MQTT_BROKER_ADDR);
+    socket.open(MQTT_BROKER_ADDR.0, MQTT_BROKER_ADDR.1).unwrap();
+    let mut mqtt = mqtt::MqttClient::new(&mut socket);
+    mqtt.connect("esp32c3-gateway", 60).unwrap();
 
+    let mut last_ping_ms = current_millis();
+
+    loop {
+        wifi_stack.work();
+
+        if let Some(data) = esp_now.receive() {
+            // Current sensor frame: [0x22, seq_lo, seq_hi, state, epoch_ms (8 bytes, little-endian)].
+            if data.data.len() == 12 && data.data[0] == 0x22 {
+                let src = data.info.src_address;
+                let mut topic = heapless::String::<32>::new();
+                let _ = core::fmt::write(
+                    &mut topic,
+                    format_args!(
+                        "movement/{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                        src[0], src[1], src[2], src[3], src[4], src[5]
+                    ),
+                );
+
+                // Forward state, the sensor's own sequence number (so the
+                // broker side can dedupe retried sends), and the event's
+                // epoch timestamp instead of a locally-tracked event counter.
+                let mut payload = [0u8; 11];
+                payload[0] = data.data[3];
+                payload[1..3].copy_from_slice(&data.data[1..3]);
+                payload[3..11].copy_from_slice(&data.data[4..12]);
+
+                match mqtt.publish(&topic, &payload) {
+                    Ok(()) => println!("Gateway: published {} bytes to {}", payload.len(), topic),
+                    Err(err) => println!("Gateway: MQTT publish failed: {:?}", err),
+                }
+            }
+        }
+
+        if current_millis() - last_ping_ms > 30_000 {
+            let _ = mqtt.ping();
+            last_ping_ms = current_millis();
+        }
+    }
 }
@@ -0,0 +1,111 @@
+//! A minimal MQTT v3.1.1 client: CONNECT, PINGREQ and PUBLISH QoS0 only.
+//! No subscribe, no QoS1/2, no reconnect logic — just enough for the gateway
+//! to push sensor updates to a broker over a plain TCP socket.
+
+use embedded_io::{Read, Write};
+use heapless::Vec;
+
+const PACKET_CONNECT: u8 = 0x10;
+const PACKET_CONNACK: u8 = 0x20;
+const PACKET_PUBLISH: u8 = 0x30;
+const PACKET_PINGREQ: u8 = 0xC0;
+const PROTOCOL_NAME: &[u8] = b"MQTT";
+const PROTOCOL_LEVEL: u8 = 4; // MQTT 3.1.1
+
+#[derive(Debug)]
+pub enum MqttError<E> {
+    Io(E),
+    ConnectRejected(u8),
+    UnexpectedPacket,
+}
+
+impl<E> From<E> for MqttError<E> {
+    fn from(e: E) -> Self {
+        MqttError::Io(e)
+    }
+}
+
+pub struct MqttClient<'a, S> {
+    socket: &'a mut S,
+}
+
+fn encode_remaining_length(len: usize, out: &mut Vec<u8, 4>) {
+    let mut len = len as u32;
+    loop {
+        let mut byte = (len % 0x80) as u8;
+        len /= 0x80;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte).ok();
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_str(buf: &mut Vec<u8, 512>, s: &str) {
+    let bytes = s.as_bytes();
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes()).ok();
+    buf.extend_from_slice(bytes).ok();
+}
+
+impl<'a, S> MqttClient<'a, S>
+where
+    S: Read + Write,
+{
+    pub fn new(socket: &'a mut S) -> Self {
+        Self { socket }
+    }
+
+    /// Sends CONNECT and waits for CONNACK.
+    pub fn connect(&mut self, client_id: &str, keep_alive_secs: u16) -> Result<(), MqttError<S::Error>> {
+        let mut variable_and_payload: Vec<u8, 512> = Vec::new();
+        let protocol_name = core::str::from_utf8(PROTOCOL_NAME).unwrap();
+        encode_str(&mut variable_and_payload, protocol_name);
+        variable_and_payload.push(PROTOCOL_LEVEL).ok();
+        variable_and_payload.push(0x02).ok(); // connect flags: clean session
+        variable_and_payload
+            .extend_from_slice(&keep_alive_secs.to_be_bytes())
+            .ok();
+        encode_str(&mut variable_and_payload, client_id);
+
+        let mut remaining_length: Vec<u8, 4> = Vec::new();
+        encode_remaining_length(variable_and_payload.len(), &mut remaining_length);
+
+        self.socket.write_all(&[PACKET_CONNECT])?;
+        self.socket.write_all(&remaining_length)?;
+        self.socket.write_all(&variable_and_payload)?;
+
+        let mut header = [0u8; 4];
+        self.socket.read_exact(&mut header)?;
+        if header[0] != PACKET_CONNACK {
+            return Err(MqttError::UnexpectedPacket);
+        }
+        if header[3] != 0 {
+            return Err(MqttError::ConnectRejected(header[3]));
+        }
+        Ok(())
+    }
+
+    /// Publishes `payload` to `topic` at QoS0 (fire and forget, no PUBACK).
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), MqttError<S::Error>> {
+        let mut variable_and_payload: Vec<u8, 512> = Vec::new();
+        encode_str(&mut variable_and_payload, topic);
+        variable_and_payload.extend_from_slice(payload).ok();
+
+        let mut remaining_length: Vec<u8, 4> = Vec::new();
+        encode_remaining_length(variable_and_payload.len(), &mut remaining_length);
+
+        self.socket.write_all(&[PACKET_PUBLISH])?;
+        self.socket.write_all(&remaining_length)?;
+        self.socket.write_all(&variable_and_payload)?;
+        Ok(())
+    }
+
+    /// Sends a PINGREQ keepalive; does not wait for PINGRESP.
+    pub fn ping(&mut self) -> Result<(), MqttError<S::Error>> {
+        self.socket.write_all(&[PACKET_PINGREQ, 0x00])?;
+        Ok(())
+    }
+}